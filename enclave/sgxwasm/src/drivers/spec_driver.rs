@@ -1,11 +1,10 @@
 use std::collections::HashMap;
 use std::prelude::v1::*;
 use wasmi::memory_units::Pages;
+use wasmi::nan_preserving_float::{F32, F64};
 
 pub use wasmi::Error as InterpreterError;
 use wasmi::{
-    Externals,
-    FuncInstance,
     FuncRef,
     GlobalDescriptor,
     GlobalInstance,
@@ -15,36 +14,56 @@ use wasmi::{
     // NopExternals,
     MemoryInstance,
     MemoryRef,
+    Module,
     ModuleImportResolver,
+    ModuleInstance,
     ModuleRef,
-    RuntimeArgs,
     RuntimeValue,
     Signature,
     TableDescriptor,
     TableInstance,
     TableRef,
     Trap,
+    TrapKind,
 };
 
 use wabt::script;
 
+use super::super::{
+    boundary_value_to_runtime_value, runtime_value_to_boundary_value, BoundaryValue,
+    ExpectedValue, NanCompare, SgxWasmAction, SpecAssertOutcome,
+};
+
 pub struct SpecModule {
     table: TableRef,
     memory: MemoryRef,
     global_i32: GlobalRef,
+    global_i64: GlobalRef,
     global_f32: GlobalRef,
     global_f64: GlobalRef,
 }
 
 impl SpecModule {
     pub fn new() -> Self {
-        SpecModule {
-            table: TableInstance::alloc(10, Some(20)).unwrap(),
-            memory: MemoryInstance::alloc(Pages(1), Some(Pages(2))).unwrap(),
+        Self::with_limits(10, Some(20), Pages(1), Some(Pages(2)))
+            .expect("the default spectest table/memory limits are always valid")
+    }
+
+    /// Builds a `SpecModule` with non-default `spectest.table`/`spectest.memory` limits.
+    pub fn with_limits(
+        table_initial: u32,
+        table_maximum: Option<u32>,
+        memory_initial: Pages,
+        memory_maximum: Option<Pages>,
+    ) -> Result<Self, InterpreterError> {
+        Ok(SpecModule {
+            table: TableInstance::alloc(table_initial, table_maximum)?,
+            memory: MemoryInstance::alloc(memory_initial, memory_maximum)?,
             global_i32: GlobalInstance::alloc(RuntimeValue::I32(666), false),
+            global_i64: GlobalInstance::alloc(RuntimeValue::I64(666), false),
             global_f32: GlobalInstance::alloc(RuntimeValue::F32(666.0.into()), false),
             global_f64: GlobalInstance::alloc(RuntimeValue::F64(666.0.into()), false),
-        }
+        })
     }
 }
 
@@ -68,21 +87,32 @@ impl From<script::Error> for Error {
     }
 }
 
-const PRINT_FUNC_INDEX: usize = 0;
+/// The spectest host functions; `host_functions` derives `Externals` and a
+/// `resolve_host_func` lookup from these typed signatures.
+#[host_functions]
+impl SpecModule {
+    fn print(&mut self) {
+        println!("print");
+    }
 
-impl Externals for SpecModule {
-    fn invoke_index(
-        &mut self,
-        index: usize,
-        args: RuntimeArgs,
-    ) -> Result<Option<RuntimeValue>, Trap> {
-        match index {
-            PRINT_FUNC_INDEX => {
-                println!("print: {:?}", args);
-                Ok(None)
-            }
-            _ => panic!("SpecModule doesn't provide function at index {}", index),
-        }
+    fn print_i32(&mut self, val: i32) {
+        println!("print_i32: {}", val);
+    }
+
+    fn print_i32_f32(&mut self, val_i32: i32, val_f32: F32) {
+        println!("print_i32_f32: {} {:?}", val_i32, val_f32);
+    }
+
+    fn print_f64_f64(&mut self, val1: F64, val2: F64) {
+        println!("print_f64_f64: {:?} {:?}", val1, val2);
+    }
+
+    fn print_f32(&mut self, val: F32) {
+        println!("print_f32: {:?}", val);
+    }
+
+    fn print_f64(&mut self, val: F64) {
+        println!("print_f64: {:?}", val);
     }
 }
 
@@ -92,29 +122,7 @@ impl ModuleImportResolver for SpecModule {
         field_name: &str,
         func_type: &Signature,
     ) -> Result<FuncRef, InterpreterError> {
-        let index = match field_name {
-            "print" => PRINT_FUNC_INDEX,
-            "print_i32" => PRINT_FUNC_INDEX,
-            "print_i32_f32" => PRINT_FUNC_INDEX,
-            "print_f64_f64" => PRINT_FUNC_INDEX,
-            "print_f32" => PRINT_FUNC_INDEX,
-            "print_f64" => PRINT_FUNC_INDEX,
-            _ => {
-                return Err(InterpreterError::Instantiation(format!(
-                    "Unknown host func import {}",
-                    field_name
-                )));
-            }
-        };
-
-        if func_type.return_type().is_some() {
-            return Err(InterpreterError::Instantiation(
-                "Function `print_` have unit return type".into(),
-            ));
-        }
-
-        let func = FuncInstance::alloc_host(func_type.clone(), index);
-        return Ok(func);
+        self.resolve_host_func(field_name, func_type)
     }
     fn resolve_global(
         &self,
@@ -123,6 +131,7 @@ impl ModuleImportResolver for SpecModule {
     ) -> Result<GlobalRef, InterpreterError> {
         match field_name {
             "global_i32" => Ok(self.global_i32.clone()),
+            "global_i64" => Ok(self.global_i64.clone()),
             "global_f32" => Ok(self.global_f32.clone()),
             "global_f64" => Ok(self.global_f64.clone()),
             _ => Err(InterpreterError::Instantiation(format!(
@@ -178,6 +187,25 @@ impl SpecDriver {
         }
     }
 
+    /// Builds a `SpecDriver` with non-default `spectest.table`/`spectest.memory` limits.
+    pub fn with_limits(
+        table_initial: u32,
+        table_maximum: Option<u32>,
+        memory_initial: Pages,
+        memory_maximum: Option<Pages>,
+    ) -> Result<SpecDriver, InterpreterError> {
+        Ok(SpecDriver {
+            spec_module: SpecModule::with_limits(
+                table_initial,
+                table_maximum,
+                memory_initial,
+                memory_maximum,
+            )?,
+            instances: HashMap::new(),
+            last_module: None,
+        })
+    }
+
     pub fn externals(&mut self) -> &mut SpecModule {
         &mut self.spec_module
     }
@@ -221,6 +249,268 @@ impl SpecDriver {
         self.add_module(Some(as_name), module);
         Ok(())
     }
+
+    fn try_load_module(wasm: &[u8]) -> Result<Module, Error> {
+        Module::from_buffer(wasm).map_err(|e| Error::Load(e.to_string()))
+    }
+
+    pub fn try_load(&mut self, wasm: &[u8]) -> Result<(), Error> {
+        let module = Self::try_load_module(wasm)?;
+        ModuleInstance::new(&module, &*self)?
+            .run_start(&mut self.spec_module)
+            .map_err(Error::Start)?;
+        Ok(())
+    }
+
+    pub fn load_module(
+        &mut self,
+        wasm: &[u8],
+        name: &Option<String>,
+    ) -> Result<ModuleRef, Error> {
+        let module = Self::try_load_module(wasm)?;
+        let instance = ModuleInstance::new(&module, &*self)?
+            .run_start(&mut self.spec_module)
+            .map_err(Error::Start)?;
+        self.add_module(name.clone(), instance.clone());
+        Ok(instance)
+    }
+
+    pub fn invoke(
+        &mut self,
+        module: &Option<String>,
+        field: &str,
+        args: &[RuntimeValue],
+    ) -> Result<Option<RuntimeValue>, InterpreterError> {
+        let instance = self.module_or_last(module.as_ref().map(|x| x.as_ref()))?;
+        instance.invoke_export(field, args, &mut self.spec_module)
+    }
+
+    pub fn get_global(
+        &self,
+        module: &Option<String>,
+        field: &str,
+    ) -> Result<RuntimeValue, InterpreterError> {
+        let instance = self.module_or_last(module.as_ref().map(|x| x.as_ref()))?;
+        let global = instance
+            .export_by_name(field)
+            .and_then(|export| export.as_global().cloned())
+            .ok_or_else(|| {
+                InterpreterError::Global(format!("Export {} is not a global", field))
+            })?;
+        Ok(global.get())
+    }
+
+    /// Runs a single non-assertion `SgxWasmAction` against the driver's state.
+    pub fn run_action(&mut self, action: &SgxWasmAction) -> Result<Option<RuntimeValue>, Error> {
+        match action {
+            SgxWasmAction::Invoke {
+                module,
+                field,
+                args,
+            } => {
+                let args: Vec<RuntimeValue> = args
+                    .iter()
+                    .map(|arg| boundary_value_to_runtime_value(*arg))
+                    .collect();
+                Ok(self.invoke(module, field, &args)?)
+            }
+            SgxWasmAction::Get { module, field } => Ok(Some(self.get_global(module, field)?)),
+            SgxWasmAction::LoadModule { name, module } => {
+                self.load_module(module, name)?;
+                Ok(None)
+            }
+            SgxWasmAction::TryLoad { module } => {
+                self.try_load(module)?;
+                Ok(None)
+            }
+            SgxWasmAction::Register { name, as_name } => {
+                self.register(name, as_name.clone())?;
+                Ok(None)
+            }
+            _ => Err(Error::Load(
+                "assertion and batch actions must be run with `run_assertion` or `run_batch`".into(),
+            )),
+        }
+    }
+
+    /// Judges one of the `SgxWasmAction::Assert*` variants against the driver's state.
+    pub fn run_assertion(&mut self, action: &SgxWasmAction) -> SpecAssertOutcome {
+        match action {
+            SgxWasmAction::AssertReturn { action, expected } => match self.run_action(action) {
+                Ok(actual) => {
+                    let actual: Vec<RuntimeValue> = actual.into_iter().collect();
+                    if actual.len() != expected.len() {
+                        return SpecAssertOutcome::Failed(format!(
+                            "expected {} return value(s), got {}",
+                            expected.len(),
+                            actual.len()
+                        ));
+                    }
+                    for (actual, expected) in actual.iter().zip(expected.iter()) {
+                        if !value_matches(actual, expected) {
+                            return SpecAssertOutcome::Failed(format!(
+                                "expected {:?}, got {:?}",
+                                expected, actual
+                            ));
+                        }
+                    }
+                    SpecAssertOutcome::Passed
+                }
+                Err(e) => SpecAssertOutcome::Failed(format!("action failed: {:?}", e)),
+            },
+            SgxWasmAction::AssertTrap { action } => match self.run_action(action) {
+                Ok(_) => SpecAssertOutcome::Failed("expected a trap, action returned".into()),
+                Err(Error::Interpreter(InterpreterError::Trap(_))) => SpecAssertOutcome::Passed,
+                Err(e) => SpecAssertOutcome::Failed(format!("not a trap: {:?}", e)),
+            },
+            SgxWasmAction::AssertExhaustion { action } => match self.run_action(action) {
+                Ok(_) => SpecAssertOutcome::Failed(
+                    "expected resource exhaustion, action returned".into(),
+                ),
+                Err(Error::Interpreter(InterpreterError::Trap(ref trap)))
+                    if *trap.kind() == TrapKind::StackOverflow =>
+                {
+                    SpecAssertOutcome::Passed
+                }
+                Err(e) => SpecAssertOutcome::Failed(format!("not resource exhaustion: {:?}", e)),
+            },
+            SgxWasmAction::AssertInvalid { module } | SgxWasmAction::AssertMalformed { module } => {
+                match Self::try_load_module(module) {
+                    Ok(_) => SpecAssertOutcome::Failed("expected module to be rejected".into()),
+                    Err(_) => SpecAssertOutcome::Passed,
+                }
+            }
+            SgxWasmAction::AssertUninstantiable { module } => match self.try_load(module) {
+                Ok(_) => {
+                    SpecAssertOutcome::Failed("expected module to fail instantiation".into())
+                }
+                Err(_) => SpecAssertOutcome::Passed,
+            },
+            _ => match self.run_action(action) {
+                Ok(_) => SpecAssertOutcome::Passed,
+                Err(e) => SpecAssertOutcome::Failed(format!("{:?}", e)),
+            },
+        }
+    }
+
+    /// Runs a sequence of actions against this driver's persistent state in
+    /// one shot, to amortize the enclave transition cost. Ordering is
+    /// preserved and a failing action does not stop the rest from running.
+    pub fn run_batch(
+        &mut self,
+        actions: &[SgxWasmAction],
+    ) -> Vec<Result<Option<BoundaryValue>, Error>> {
+        let mut results = Vec::with_capacity(actions.len());
+        for action in actions {
+            match action {
+                SgxWasmAction::Batch(inner) => results.extend(self.run_batch(inner)),
+                _ => results.push(self.run_one(action)),
+            }
+        }
+        results
+    }
+
+    fn run_one(&mut self, action: &SgxWasmAction) -> Result<Option<BoundaryValue>, Error> {
+        match action {
+            SgxWasmAction::AssertReturn { .. }
+            | SgxWasmAction::AssertTrap { .. }
+            | SgxWasmAction::AssertInvalid { .. }
+            | SgxWasmAction::AssertMalformed { .. }
+            | SgxWasmAction::AssertUninstantiable { .. }
+            | SgxWasmAction::AssertExhaustion { .. } => match self.run_assertion(action) {
+                SpecAssertOutcome::Passed => Ok(None),
+                SpecAssertOutcome::Failed(msg) => Err(Error::Load(msg)),
+            },
+            _ => self
+                .run_action(action)
+                .map(|value| value.map(runtime_value_to_boundary_value)),
+        }
+    }
+}
+
+fn value_matches(actual: &RuntimeValue, expected: &ExpectedValue) -> bool {
+    match (actual, expected) {
+        (RuntimeValue::I32(a), ExpectedValue::I32(e)) => a == e,
+        (RuntimeValue::I64(a), ExpectedValue::I64(e)) => a == e,
+        (RuntimeValue::F32(a), ExpectedValue::F32(e)) => f32_matches(a.to_bits(), e),
+        (RuntimeValue::F64(a), ExpectedValue::F64(e)) => f64_matches(a.to_bits(), e),
+        (RuntimeValue::V128(a), ExpectedValue::V128(e)) => a == e,
+        _ => false,
+    }
+}
+
+fn f32_matches(bits: u32, expected: &NanCompare<u32>) -> bool {
+    const SIGN_MASK: u32 = 0x7fff_ffff;
+    const CANONICAL: u32 = 0x7fc0_0000;
+    // Exponent all ones plus the quiet bit; matching against this whole
+    // mask (not just the quiet bit alone) is what keeps non-NaN inputs out.
+    const NAN_AND_QUIET_MASK: u32 = 0x7fc0_0000;
+    match expected {
+        NanCompare::Exact(e) => bits == *e,
+        NanCompare::CanonicalNan => bits & SIGN_MASK == CANONICAL,
+        NanCompare::ArithmeticNan => bits & NAN_AND_QUIET_MASK == NAN_AND_QUIET_MASK,
+    }
+}
+
+fn f64_matches(bits: u64, expected: &NanCompare<u64>) -> bool {
+    const SIGN_MASK: u64 = 0x7fff_ffff_ffff_ffff;
+    const CANONICAL: u64 = 0x7ff8_0000_0000_0000;
+    // Exponent all ones plus the quiet bit; matching against this whole
+    // mask (not just the quiet bit alone) is what keeps non-NaN inputs out.
+    const NAN_AND_QUIET_MASK: u64 = 0x7ff8_0000_0000_0000;
+    match expected {
+        NanCompare::Exact(e) => bits == *e,
+        NanCompare::CanonicalNan => bits & SIGN_MASK == CANONICAL,
+        NanCompare::ArithmeticNan => bits & NAN_AND_QUIET_MASK == NAN_AND_QUIET_MASK,
+    }
+}
+
+#[cfg(test)]
+mod nan_compare_tests {
+    use super::{f32_matches, f64_matches, NanCompare};
+
+    #[test]
+    fn f32_canonical_nan_ignores_sign() {
+        assert!(f32_matches(0x7fc0_0000, &NanCompare::CanonicalNan));
+        assert!(f32_matches(0xffc0_0000, &NanCompare::CanonicalNan));
+        assert!(!f32_matches(0x7fc0_0001, &NanCompare::CanonicalNan));
+        assert!(!f32_matches(0x7f80_0001, &NanCompare::CanonicalNan));
+    }
+
+    #[test]
+    fn f32_arithmetic_nan_only_requires_quiet_bit() {
+        assert!(f32_matches(0x7fc0_0001, &NanCompare::ArithmeticNan));
+        assert!(f32_matches(0xffd0_0000, &NanCompare::ArithmeticNan));
+        assert!(!f32_matches(0x7f80_0001, &NanCompare::ArithmeticNan));
+    }
+
+    #[test]
+    fn f64_canonical_nan_ignores_sign() {
+        assert!(f64_matches(
+            0x7ff8_0000_0000_0000,
+            &NanCompare::CanonicalNan
+        ));
+        assert!(f64_matches(
+            0xfff8_0000_0000_0000,
+            &NanCompare::CanonicalNan
+        ));
+        assert!(!f64_matches(
+            0x7ff0_0000_0000_0001,
+            &NanCompare::CanonicalNan
+        ));
+    }
+
+    #[test]
+    fn f64_arithmetic_nan_only_requires_quiet_bit() {
+        assert!(f64_matches(
+            0x7ff8_0000_0000_0001,
+            &NanCompare::ArithmeticNan
+        ));
+        assert!(!f64_matches(
+            0x7ff0_0000_0000_0001,
+            &NanCompare::ArithmeticNan
+        ));
+    }
 }
 
 impl ImportResolver for SpecDriver {
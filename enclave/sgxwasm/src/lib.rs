@@ -28,6 +28,8 @@ extern crate sgx_tstd as std;
 extern crate lazy_static;
 extern crate wabt;
 extern crate wasmi;
+#[macro_use]
+extern crate wasmi_derive;
 
 pub mod drivers;
 
@@ -64,9 +66,31 @@ pub enum SgxWasmAction {
         name: Option<String>,
         as_name: String,
     },
+    AssertReturn {
+        action: Box<SgxWasmAction>,
+        expected: Vec<ExpectedValue>,
+    },
+    AssertTrap {
+        action: Box<SgxWasmAction>,
+    },
+    AssertInvalid {
+        module: Vec<u8>,
+    },
+    AssertMalformed {
+        module: Vec<u8>,
+    },
+    AssertUninstantiable {
+        module: Vec<u8>,
+    },
+    AssertExhaustion {
+        action: Box<SgxWasmAction>,
+    },
+    /// A sequence of actions run against one `SpecDriver` in a single ECALL;
+    /// see `SpecDriver::run_batch`.
+    Batch(Vec<SgxWasmAction>),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum BoundaryValue {
     I32(i32),
     I64(i64),
@@ -75,13 +99,40 @@ pub enum BoundaryValue {
     V128(u128),
 }
 
+/// A float result expected by `AssertReturn`: an exact bit pattern, or a NaN
+/// pattern (`CanonicalNan`: exponent all ones, only the top mantissa bit set,
+/// sign ignored; `ArithmeticNan`: at least that quiet bit set).
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum NanCompare<T> {
+    Exact(T),
+    CanonicalNan,
+    ArithmeticNan,
+}
+
+/// Like `BoundaryValue`, but for the expected side of an `AssertReturn`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum ExpectedValue {
+    I32(i32),
+    I64(i64),
+    F32(NanCompare<u32>),
+    F64(NanCompare<u64>),
+    V128(u128),
+}
+
+/// The outcome of an `SgxWasmAction::Assert*` variant.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SpecAssertOutcome {
+    Passed,
+    Failed(String),
+}
+
 pub fn runtime_value_to_boundary_value(rv: RuntimeValue) -> BoundaryValue {
     match rv {
         RuntimeValue::I32(rv) => BoundaryValue::I32(rv),
         RuntimeValue::I64(rv) => BoundaryValue::I64(rv),
         RuntimeValue::F32(rv) => BoundaryValue::F32(rv.to_bits()),
         RuntimeValue::F64(rv) => BoundaryValue::F64(rv.to_bits()),
-        //RuntimeValue::V128(rv) => BoundaryValue::V128(rv),
+        RuntimeValue::V128(rv) => BoundaryValue::V128(rv),
     }
 }
 
@@ -91,7 +142,7 @@ pub fn boundary_value_to_runtime_value(rv: BoundaryValue) -> RuntimeValue {
         BoundaryValue::I64(bv) => RuntimeValue::I64(bv),
         BoundaryValue::F32(bv) => RuntimeValue::F32(f32::from_bits(bv).into()),
         BoundaryValue::F64(bv) => RuntimeValue::F64(f64::from_bits(bv).into()),
-        BoundaryValue::V128(bv) => panic!("Not supported yet!"),
+        BoundaryValue::V128(bv) => RuntimeValue::V128(bv),
     }
 }
 